@@ -5,7 +5,11 @@ use lazy_static::lazy_static;
 use log::debug;
 #[cfg(feature = "timeout")]
 use parking_lot::RwLock;
-use std::sync::{atomic::AtomicU32, Arc};
+#[cfg(feature = "fair")]
+use std::collections::VecDeque;
+#[cfg(feature = "fair")]
+use std::sync::atomic::AtomicU64;
+use std::sync::{atomic::AtomicU32, Arc, Condvar, Mutex};
 #[cfg(feature = "timeout")]
 use std::time::Duration;
 #[cfg(feature = "timeout")]
@@ -14,16 +18,180 @@ use std::time::Instant;
 pub(crate) struct UniqueReentrantMutex {
     locks: Locks,
 
+    // The key name this mutex is registered under in `LOCKS`. Only needed so the fair
+    // ticket queue below can look up this key's configured wait_duration.
+    #[allow(dead_code)]
+    name: String,
+
+    // Ticket dispenser and queue state for the opt-in fair mode: a waiter draws
+    // `next_ticket` and pushes it onto the back of `fair_state.queue`, and is only let
+    // through once it reaches the front, so the lock is handed off in strict
+    // first-come-first-served order instead of letting everyone re-race on release. Unlike
+    // a bare "now serving" counter, a waiter that gives up (e.g. on timeout) can remove its
+    // own ticket from the queue without permanently wedging everyone behind it.
+    // `current_holder`/`depth` track same-thread reentrancy so a nested `lock()` call from
+    // the thread that already holds this key doesn't draw a new ticket and deadlock
+    // waiting for itself.
+    #[cfg(feature = "fair")]
+    next_ticket: AtomicU64,
+    #[cfg(feature = "fair")]
+    fair_state: Mutex<FairState>,
+    #[cfg(feature = "fair")]
+    fair_condvar: Condvar,
+
     // Only actually used for tests
     #[allow(dead_code)]
     pub(crate) id: u32,
 }
 
+#[cfg(feature = "fair")]
+struct FairState {
+    queue: VecDeque<u64>,
+    current_holder: Option<std::thread::ThreadId>,
+    depth: u32,
+}
+
 impl UniqueReentrantMutex {
+    fn new(name: impl Into<String>) -> Self {
+        Self {
+            locks: Locks::new(),
+            name: name.into(),
+            #[cfg(feature = "fair")]
+            next_ticket: AtomicU64::new(0),
+            #[cfg(feature = "fair")]
+            fair_state: Mutex::new(FairState {
+                queue: VecDeque::new(),
+                current_holder: None,
+                depth: 0,
+            }),
+            #[cfg(feature = "fair")]
+            fair_condvar: Condvar::new(),
+            id: MUTEX_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+        }
+    }
+
+    #[cfg(not(feature = "fair"))]
     pub(crate) fn lock(&self) -> MutexGuardWrapper {
         self.locks.serial()
     }
 
+    #[cfg(feature = "fair")]
+    pub(crate) fn lock(&self) -> FairMutexGuard<'_> {
+        #[cfg(feature = "timeout")]
+        match self.try_lock_fair() {
+            Ok(guard) => guard,
+            Err(timeout) => panic!("{}", timeout),
+        }
+
+        #[cfg(not(feature = "timeout"))]
+        self.try_lock_fair()
+    }
+
+    /// Same as [`lock`](Self::lock), but returns a [`SerialTimeout`] instead of panicking
+    /// if our turn never comes up before the configured wait duration elapses.
+    ///
+    /// Only fallible when the `timeout` feature is enabled; without it, this waits for its
+    /// turn indefinitely and returns the guard directly.
+    #[cfg(all(feature = "fair", feature = "timeout"))]
+    pub(crate) fn try_lock_fair(&self) -> Result<FairMutexGuard<'_>, SerialTimeout> {
+        let this_thread = std::thread::current().id();
+        let mut state = self
+            .fair_state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if state.current_holder == Some(this_thread) {
+            // Reentrant acquisition by the thread that already holds this key's lock:
+            // skip the ticket queue entirely, since waiting for our own turn to come up
+            // again would deadlock forever.
+            state.depth += 1;
+            drop(state);
+            return Ok(FairMutexGuard {
+                inner: Some(self.locks.serial()),
+                mutex: self,
+            });
+        }
+
+        let my_ticket = self
+            .next_ticket
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        state.queue.push_back(my_ticket);
+
+        let start = Instant::now();
+        loop {
+            if state.current_holder.is_none() && state.queue.front() == Some(&my_ticket) {
+                state.queue.pop_front();
+                state.current_holder = Some(this_thread);
+                state.depth = 1;
+                drop(state);
+                return Ok(FairMutexGuard {
+                    inner: Some(self.locks.serial()),
+                    mutex: self,
+                });
+            }
+
+            let remaining = match wait_duration(&self.name).checked_sub(start.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => {
+                    // Remove our own ticket from the queue instead of leaving it stuck at
+                    // the front forever, which would otherwise permanently wedge every
+                    // waiter behind us even once the lock is free.
+                    state.queue.retain(|&ticket| ticket != my_ticket);
+                    drop(state);
+                    self.fair_condvar.notify_all();
+                    return Err(SerialTimeout {
+                        name: self.name.clone(),
+                        waited: start.elapsed(),
+                    });
+                }
+            };
+            state = self
+                .fair_condvar
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    #[cfg(all(feature = "fair", not(feature = "timeout")))]
+    fn try_lock_fair(&self) -> FairMutexGuard<'_> {
+        let this_thread = std::thread::current().id();
+        let mut state = self
+            .fair_state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if state.current_holder == Some(this_thread) {
+            // Reentrant acquisition by the thread that already holds this key's lock:
+            // skip the ticket queue entirely, since waiting for our own turn to come up
+            // again would deadlock forever.
+            state.depth += 1;
+        } else {
+            let my_ticket = self
+                .next_ticket
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            state.queue.push_back(my_ticket);
+
+            while !(state.current_holder.is_none() && state.queue.front() == Some(&my_ticket)) {
+                state = self
+                    .fair_condvar
+                    .wait(state)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+
+            state.queue.pop_front();
+            state.current_holder = Some(this_thread);
+            state.depth = 1;
+        }
+
+        drop(state);
+
+        FairMutexGuard {
+            inner: Some(self.locks.serial()),
+            mutex: self,
+        }
+    }
+
     pub(crate) fn start_parallel(&self) {
         self.locks.start_parallel();
     }
@@ -43,22 +211,107 @@ impl UniqueReentrantMutex {
     }
 }
 
+// One (mutex, condvar) pair per key name. The mutex is held by whichever thread is either
+// creating the key or checking-then-waiting for it to appear, so that a writer's
+// insert-and-notify can never race past another waiter's check-then-wait and get lost.
+type KeyWaiter = Arc<(Mutex<()>, Condvar)>;
+
 lazy_static! {
     pub(crate) static ref LOCKS: Arc<DashMap<String, UniqueReentrantMutex>> =
         Arc::new(DashMap::new());
     static ref MUTEX_ID: Arc<AtomicU32> = Arc::new(AtomicU32::new(1));
+    static ref KEY_WAITERS: Arc<DashMap<String, KeyWaiter>> = Arc::new(DashMap::new());
+}
+
+fn waiter_for(name: &str) -> KeyWaiter {
+    KEY_WAITERS
+        .entry(name.to_string())
+        .or_insert_with(|| Arc::new((Mutex::new(()), Condvar::new())))
+        .clone()
 }
 
 #[cfg(feature = "timeout")]
 lazy_static! {
     static ref MAX_WAIT: Arc<RwLock<Duration>> = Arc::new(RwLock::new(Duration::from_secs(60)));
+    static ref MAX_WAIT_FOR: Arc<DashMap<String, Duration>> = Arc::new(DashMap::new());
+}
+
+/// The error returned when a serial test times out waiting for its lock, instead of the
+/// default behavior of panicking. See [`check_serial_timeout`].
+#[cfg(feature = "timeout")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialTimeout {
+    /// The name of the key that was being waited on.
+    pub name: String,
+    /// How long we waited before giving up.
+    pub waited: Duration,
+}
+
+#[cfg(feature = "timeout")]
+impl std::fmt::Display for SerialTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Timeout waiting for '{}' {:?}",
+            self.name, self.waited
+        )
+    }
 }
 
+#[cfg(feature = "timeout")]
+impl std::error::Error for SerialTimeout {}
+
 impl Default for UniqueReentrantMutex {
     fn default() -> Self {
-        Self {
-            locks: Locks::new(),
-            id: MUTEX_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+        Self::new(String::new())
+    }
+}
+
+/// A [`MutexGuardWrapper`] that also holds this key's place in the fair-mode ticket
+/// queue, releasing it to the next waiter on drop.
+///
+/// Only exists when the `fair` feature is enabled.
+#[cfg(feature = "fair")]
+pub(crate) struct FairMutexGuard<'a> {
+    inner: Option<MutexGuardWrapper<'a>>,
+    mutex: &'a UniqueReentrantMutex,
+}
+
+#[cfg(feature = "fair")]
+impl<'a> std::ops::Deref for FairMutexGuard<'a> {
+    type Target = MutexGuardWrapper<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        self.inner.as_ref().expect("guard taken before drop")
+    }
+}
+
+#[cfg(feature = "fair")]
+impl<'a> std::ops::DerefMut for FairMutexGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.inner.as_mut().expect("guard taken before drop")
+    }
+}
+
+#[cfg(feature = "fair")]
+impl<'a> Drop for FairMutexGuard<'a> {
+    fn drop(&mut self) {
+        // Drop the underlying lock before serving the next ticket, so the next waiter
+        // doesn't wake up to find the mutex still held.
+        self.inner.take();
+
+        let mut state = self
+            .mutex
+            .fair_state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.depth -= 1;
+        if state.depth == 0 {
+            // Only the outermost reentrant guard for this thread releases the lock, letting
+            // whichever ticket is now at the front of the queue (if any) proceed.
+            state.current_holder = None;
+            drop(state);
+            self.mutex.fair_condvar.notify_all();
         }
     }
 }
@@ -77,21 +330,101 @@ pub fn set_max_wait(max_wait: Duration) {
     *MAX_WAIT.write() = max_wait;
 }
 
+/// Sets the maximum amount of time the serial locks will wait to unlock for a specific
+/// key name, overriding the global default set by [`set_max_wait`] for that key only.
+///
+/// This function is only available when the `timeout` feature is enabled.
+#[cfg(feature = "timeout")]
+pub fn set_max_wait_for(name: impl Into<String>, max_wait: Duration) {
+    MAX_WAIT_FOR.insert(name.into(), max_wait);
+}
+
 #[cfg(feature = "timeout")]
-pub(crate) fn wait_duration() -> Duration {
-    *MAX_WAIT.read()
+pub(crate) fn wait_duration(name: &str) -> Duration {
+    match MAX_WAIT_FOR.get(name) {
+        Some(max_wait) => *max_wait,
+        None => *MAX_WAIT.read(),
+    }
 }
 
 pub(crate) fn check_new_key(name: &str) {
     #[cfg(feature = "timeout")]
+    match check_new_key_fallible(name) {
+        Ok(()) => {}
+        Err(timeout) => panic!("{}", timeout),
+    }
+
+    #[cfg(not(feature = "timeout"))]
+    check_new_key_fallible(name);
+}
+
+/// Same as [`check_new_key`], but returns a [`SerialTimeout`] on timeout instead of
+/// panicking, so callers can report it however suits their test harness. Also exposed
+/// publicly as [`check_serial_timeout`].
+///
+/// Only fallible when the `timeout` feature is enabled; without it, waiting never times
+/// out and this returns `()`.
+#[cfg(feature = "timeout")]
+pub(crate) fn check_new_key_fallible(name: &str) -> Result<(), SerialTimeout> {
     let start = Instant::now();
     loop {
-        #[cfg(all(feature = "logging", feature = "timeout"))]
+        #[cfg(feature = "logging")]
         {
             let duration = start.elapsed();
             debug!("Waiting for '{}' {:?}", name, duration);
         }
         // Check if a new key is needed. Just need a read lock, which can be done in sync with everyone else
+        match LOCKS.try_get(name) {
+            TryResult::Present(_) => {
+                return Ok(());
+            }
+            TryResult::Locked => {
+                continue; // wasn't able to get read lock
+            }
+            TryResult::Absent => {} // do the write path below
+        };
+
+        // Everything below - creating the key, or checking-then-waiting for it - happens
+        // while holding this key's own mutex, so a writer's insert-and-notify can never
+        // race past another waiter's check-then-wait and get lost.
+        let waiter = waiter_for(name);
+        let (mutex, condvar) = &*waiter;
+        let guard = mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(entry) = LOCKS.try_entry(name.to_string()) {
+            entry.or_insert_with(|| UniqueReentrantMutex::new(name));
+            // Wake up any other threads that were waiting for this key to appear.
+            condvar.notify_all();
+            return Ok(());
+        }
+
+        // Someone else is creating this key right now. Recheck under the same lock that
+        // guards the notify above, immediately before waiting, so we can't miss a wakeup
+        // that already fired between our first `try_get` and taking this lock.
+        if matches!(LOCKS.try_get(name), TryResult::Present(_)) {
+            continue;
+        }
+
+        let remaining = match wait_duration(name).checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => {
+                return Err(SerialTimeout {
+                    name: name.to_string(),
+                    waited: start.elapsed(),
+                })
+            }
+        };
+        drop(
+            condvar
+                .wait_timeout(guard, remaining)
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+    }
+}
+
+#[cfg(not(feature = "timeout"))]
+fn check_new_key_fallible(name: &str) {
+    loop {
         match LOCKS.try_get(name) {
             TryResult::Present(_) => {
                 return;
@@ -102,23 +435,177 @@ pub(crate) fn check_new_key(name: &str) {
             TryResult::Absent => {} // do the write path below
         };
 
-        // This is the rare path, which avoids the multi-writer situation mostly
-        let try_entry = LOCKS.try_entry(name.to_string());
+        let waiter = waiter_for(name);
+        let (mutex, condvar) = &*waiter;
+        let guard = mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
 
-        if let Some(entry) = try_entry {
-            entry.or_default();
+        if let Some(entry) = LOCKS.try_entry(name.to_string()) {
+            entry.or_insert_with(|| UniqueReentrantMutex::new(name));
+            condvar.notify_all();
             return;
         }
 
-        // If the try_entry fails, then go around the loop again
-        // Odds are another test was also locking on the write and has now written the key
+        if matches!(LOCKS.try_get(name), TryResult::Present(_)) {
+            continue;
+        }
 
-        #[cfg(feature = "timeout")]
-        {
-            let duration = start.elapsed();
-            if duration > wait_duration() {
-                panic!("Timeout waiting for '{}' {:?}", name, duration);
-            }
+        drop(
+            condvar
+                .wait(guard)
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+    }
+}
+
+/// Checks that the serial lock for `name` has been created, waiting for it if necessary -
+/// the same thing the `#[serial]`/`#[parallel]` attributes do internally - except that on
+/// timeout this returns a [`SerialTimeout`] instead of panicking. Call this directly if you
+/// want to handle a timed-out serial lock as a recoverable error rather than an unwinding
+/// panic.
+///
+/// This function is only available when the `timeout` feature is enabled.
+#[cfg(feature = "timeout")]
+pub fn check_serial_timeout(name: &str) -> Result<(), SerialTimeout> {
+    check_new_key_fallible(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unique_name() -> String {
+        format!(
+            "code_lock-test-{}",
+            MUTEX_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        )
+    }
+
+    #[test]
+    fn many_threads_racing_to_create_the_same_key_only_create_it_once() {
+        let name = unique_name();
+
+        let handles: Vec<_> = (0..32)
+            .map(|_| {
+                let name = name.clone();
+                std::thread::spawn(move || {
+                    check_new_key(&name);
+                    LOCKS.get(&name).expect("key should exist once checked").id
+                })
+            })
+            .collect();
+
+        let ids: Vec<u32> = handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .expect("thread should not panic while racing to create the key")
+            })
+            .collect();
+
+        assert!(matches!(LOCKS.try_get(&name), TryResult::Present(_)));
+        // Every thread must have seen the same mutex, i.e. the key was created once.
+        assert!(ids.iter().all(|&id| id == ids[0]));
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn wait_duration_prefers_the_per_key_override_over_the_global_default() {
+        let overridden = unique_name();
+        let not_overridden = unique_name();
+        let global_before = wait_duration(&not_overridden);
+
+        set_max_wait_for(overridden.clone(), Duration::from_millis(5));
+
+        assert_eq!(wait_duration(&overridden), Duration::from_millis(5));
+        assert_eq!(wait_duration(&not_overridden), global_before);
+    }
+
+    #[cfg(feature = "fair")]
+    #[test]
+    fn fair_mode_grants_the_lock_in_first_come_first_served_order() {
+        let mutex = Arc::new(UniqueReentrantMutex::new(unique_name()));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // Hold the lock up front so every spawned thread queues up behind it, in the order
+        // it's spawned, instead of racing each other to go first.
+        let gatekeeper = mutex.lock();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let mutex = Arc::clone(&mutex);
+                let order = Arc::clone(&order);
+                let handle = std::thread::spawn(move || {
+                    let _guard = mutex.lock();
+                    order.lock().unwrap_or_else(|p| p.into_inner()).push(i);
+                });
+                // Give each thread a moment to enqueue before spawning the next one, so
+                // the queue order matches spawn order.
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                handle
+            })
+            .collect();
+
+        drop(gatekeeper);
+
+        for handle in handles {
+            handle
+                .join()
+                .expect("thread should not panic while waiting for its turn");
         }
+
+        assert_eq!(
+            *order.lock().unwrap_or_else(|p| p.into_inner()),
+            (0..8).collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "fair")]
+    #[test]
+    fn fair_mode_allows_reentrant_locking_from_the_same_thread() {
+        let mutex = UniqueReentrantMutex::new(unique_name());
+
+        let _outer = mutex.lock();
+        let _inner = mutex.lock(); // must not deadlock against ourselves
+    }
+
+    #[cfg(all(feature = "fair", feature = "timeout"))]
+    #[test]
+    fn fair_mode_does_not_wedge_after_a_waiter_times_out() {
+        let name = unique_name();
+        set_max_wait_for(name.clone(), Duration::from_millis(50));
+        let mutex = Arc::new(UniqueReentrantMutex::new(name));
+
+        let release = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let holder = Arc::clone(&mutex);
+        let release_for_holder = Arc::clone(&release);
+        let holder_thread = std::thread::spawn(move || {
+            let _guard = holder.lock();
+            while !release_for_holder.load(std::sync::atomic::Ordering::Acquire) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        // Give the holder thread time to acquire the lock first.
+        std::thread::sleep(Duration::from_millis(10));
+
+        let timed_out = Arc::clone(&mutex);
+        let result = std::thread::spawn(move || timed_out.try_lock_fair())
+            .join()
+            .expect("waiting thread should not panic");
+        assert!(
+            result.is_err(),
+            "waiter should have timed out while the lock was still held"
+        );
+
+        release.store(true, std::sync::atomic::Ordering::Release);
+        holder_thread
+            .join()
+            .expect("holder thread should not panic");
+
+        // A fresh, uncontended acquisition must still succeed promptly - the timed-out
+        // waiter must not have left its ticket stuck at the front of the queue.
+        let _guard = mutex.lock();
     }
 }